@@ -0,0 +1,236 @@
+//! Parses the ATerm representation of a realized `.drv` file.
+//!
+//! This lets us recover the exact set of input sources and input
+//! derivations of a derivation without relying on the evaluator’s
+//! log output, which is what `instrumented_builder`’s regex-based
+//! `parse_evaluation_line` otherwise has to do.
+//!
+//! The grammar we parse (as emitted by `nix show-derivation`’s
+//! underlying ATerm writer) is:
+//!
+//! ```text
+//! Derive([outputs],[inputDrvs],[inputSrcs],platform,builder,[args],[(key,val)...])
+//! ```
+//!
+//! where
+//! - each output is `(name,path,hashAlgo,hash)`
+//! - each inputDrv is `(drvPath,[outputName,...])`
+//! - inputSrcs is a list of store-path strings
+//! - all strings are double-quoted, with `\"`, `\n`, `\t`, `\r`, `\\` escapes
+
+use nom::branch::alt;
+use nom::bytes::complete::{escaped_transform, tag};
+use nom::character::complete::{char, none_of};
+use nom::combinator::{map, value};
+use nom::multi::separated_list0;
+use nom::sequence::{delimited, preceded, separated_pair, tuple};
+use nom::IResult;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use DrvFile;
+
+/// The parts of a realized `.drv` file that we care about: the
+/// derivations and source files that must be present (and watched)
+/// for the build to succeed.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Derivation {
+    /// Input derivations, each with the output names of itself
+    /// that are actually used.
+    pub input_drvs: Vec<(PathBuf, Vec<String>)>,
+    /// Input store paths that are not themselves derivations
+    /// (plain sources copied into the store).
+    pub input_srcs: Vec<PathBuf>,
+}
+
+/// Parse a double-quoted ATerm string, unescaping `\"`, `\n`, `\t`, `\r` and `\\`.
+fn quoted_string(input: &str) -> IResult<&str, String> {
+    delimited(
+        char('"'),
+        // an empty string has nothing to `escaped_transform`, so allow that too
+        alt((
+            escaped_transform(
+                none_of("\"\\"),
+                '\\',
+                alt((
+                    value("\"", tag("\"")),
+                    value("\n", tag("n")),
+                    value("\t", tag("t")),
+                    value("\r", tag("r")),
+                    value("\\", tag("\\")),
+                )),
+            ),
+            map(tag(""), |_| String::new()),
+        )),
+        char('"'),
+    )(input)
+}
+
+/// Parse a comma-separated list delimited by `[` and `]`.
+fn list0<'a, O>(
+    item: impl FnMut(&'a str) -> IResult<&'a str, O> + Copy,
+) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<O>> {
+    move |input| delimited(char('['), separated_list0(char(','), item), char(']'))(input)
+}
+
+/// Parse a parenthesized tuple of two fields.
+fn pair<'a, O1, O2>(
+    first: impl FnMut(&'a str) -> IResult<&'a str, O1>,
+    second: impl FnMut(&'a str) -> IResult<&'a str, O2>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, (O1, O2)> {
+    delimited(char('('), separated_pair(first, char(','), second), char(')'))
+}
+
+/// `(drvPath,[outputName,...])`
+fn input_drv(input: &str) -> IResult<&str, (PathBuf, Vec<String>)> {
+    map(
+        pair(quoted_string, list0(quoted_string)),
+        |(path, outputs)| (PathBuf::from(path), outputs),
+    )(input)
+}
+
+/// `(name,path,hashAlgo,hash)`, we only need the path.
+fn output(input: &str) -> IResult<&str, PathBuf> {
+    map(
+        delimited(
+            char('('),
+            tuple((
+                quoted_string,
+                preceded(char(','), quoted_string),
+                preceded(char(','), quoted_string),
+                preceded(char(','), quoted_string),
+            )),
+            char(')'),
+        ),
+        |(_name, path, _hash_algo, _hash)| PathBuf::from(path),
+    )(input)
+}
+
+/// Parse a realized `.drv` file’s ATerm contents into a `Derivation`.
+///
+/// We only extract `inputDrvs` and `inputSrcs`; the outputs, platform,
+/// builder, args and environment are parsed (to stay in sync with the
+/// cursor) but discarded.
+pub fn parse_derivation(input: &str) -> IResult<&str, Derivation> {
+    map(
+        preceded(
+            tag("Derive"),
+            delimited(
+                char('('),
+                tuple((
+                    list0(output),
+                    preceded(char(','), list0(input_drv)),
+                    preceded(char(','), list0(quoted_string)),
+                    preceded(char(','), quoted_string), // platform
+                    preceded(char(','), quoted_string), // builder
+                    preceded(char(','), list0(quoted_string)), // args
+                    preceded(
+                        char(','),
+                        list0(|i| pair(quoted_string, quoted_string)(i)), // env
+                    ),
+                )),
+                char(')'),
+            ),
+        ),
+        |(_outputs, input_drvs, input_srcs, _platform, _builder, _args, _env)| Derivation {
+            input_drvs,
+            input_srcs: input_srcs.into_iter().map(PathBuf::from).collect(),
+        },
+    )(input)
+}
+
+/// Read and parse a `.drv` file from disk.
+pub fn read_derivation(path: &Path) -> Result<Derivation, ReadDrvError> {
+    let contents = std::fs::read_to_string(path)?;
+    let (_rest, drv) = parse_derivation(&contents)
+        .map_err(|e| ReadDrvError::Parse(path.to_path_buf(), e.to_string()))?;
+    Ok(drv)
+}
+
+/// Transitively walk `root`’s `inputDrvs`, reading each referenced
+/// `.drv` file in turn, and return every source file and derivation
+/// file in the closure (including `root` itself).
+///
+/// `instrumented_builder::run` adds these paths to the source paths
+/// scraped out of `nix-instantiate -vv`’s stderr.
+pub fn transitive_closure(root: &DrvFile) -> Result<Vec<PathBuf>, ReadDrvError> {
+    let mut seen_drvs = HashSet::new();
+    let mut paths = vec![];
+    let mut queue = vec![root.as_path().to_path_buf()];
+
+    while let Some(drv_path) = queue.pop() {
+        if !seen_drvs.insert(drv_path.clone()) {
+            continue;
+        }
+        paths.push(drv_path.clone());
+
+        let drv = read_derivation(&drv_path)?;
+        for src in drv.input_srcs {
+            paths.push(src);
+        }
+        for (input_drv, _outputs) in drv.input_drvs {
+            queue.push(input_drv);
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Errors that can occur while reading or parsing a `.drv` file.
+#[derive(Debug)]
+pub enum ReadDrvError {
+    /// Could not read the `.drv` file from disk.
+    Io(std::io::Error),
+    /// Could not parse the `.drv` file’s ATerm contents.
+    Parse(PathBuf, String),
+}
+
+impl From<std::io::Error> for ReadDrvError {
+    fn from(e: std::io::Error) -> ReadDrvError {
+        ReadDrvError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_minimal_derivation() {
+        let aterm = r#"Derive([("out","/nix/store/abc-out","","")],[("/nix/store/dep.drv",["out"])],["/nix/store/src"],"x86_64-linux","/bin/sh",["-c","true"],[("PATH","")])"#;
+        let (rest, drv) = parse_derivation(aterm).expect("should parse");
+        assert_eq!(rest, "");
+        assert_eq!(
+            drv,
+            Derivation {
+                input_drvs: vec![(
+                    PathBuf::from("/nix/store/dep.drv"),
+                    vec!["out".to_string()]
+                )],
+                input_srcs: vec![PathBuf::from("/nix/store/src")],
+            }
+        );
+    }
+
+    #[test]
+    fn parses_escaped_strings() {
+        let aterm = r#"Derive([],[],[],"x","y",[],[("k","a\"b\nc\\d")])"#;
+        let (rest, drv) = parse_derivation(aterm).expect("should parse");
+        assert_eq!(rest, "");
+        assert_eq!(drv.input_drvs, vec![]);
+        assert_eq!(drv.input_srcs, vec![]);
+    }
+
+    #[test]
+    fn parses_empty_quoted_string() {
+        let (rest, s) = quoted_string("\"\"").expect("should parse");
+        assert_eq!(rest, "");
+        assert_eq!(s, "");
+    }
+
+    #[test]
+    fn unescapes_all_known_escape_sequences() {
+        let (rest, s) = quoted_string(r#""a\"b\nc\td\re\\f""#).expect("should parse");
+        assert_eq!(rest, "");
+        assert_eq!(s, "a\"b\nc\td\re\\f");
+    }
+}
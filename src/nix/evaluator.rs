@@ -0,0 +1,63 @@
+//! Abstracts the Nix evaluator behind a trait, so `instrumented_builder::run`
+//! doesn’t have to hard-code a `nix-instantiate` subprocess call.
+//!
+//! Two implementations shell out to the C++ Nix CLI today —
+//! `NixCliEvaluator` (regex-matching `-vv` stderr) and
+//! `NixCliInternalJsonEvaluator` (decoding the machine-readable
+//! `--log-format internal-json` envelope instead of raw `-vv` text; see that
+//! type's doc comment for exactly what this does and doesn't buy us). This
+//! trait is also the extension point for a third, entirely different
+//! backend (e.g. a tvix/lix-style in-process evaluator) that can report
+//! evaluated source paths through a structured callback instead.
+
+use super::instrumented_builder::{self, Error, Info, OutputPaths};
+use cas::ContentAddressable;
+use {DrvFile, NixFile};
+
+/// Evaluates a Nix expression (instrumented via `logged-evaluation.nix`)
+/// and reports its output derivations, or a `Failure`/`Error`.
+pub trait Evaluator {
+    /// Instantiate `root`, returning the derivations it produced.
+    fn instantiate(
+        &self,
+        root: &NixFile,
+        cas: &ContentAddressable,
+    ) -> Result<Info<OutputPaths<DrvFile>>, Error>;
+}
+
+/// The original evaluator: runs `nix-instantiate -vv` and recovers
+/// source paths and output attributes by regex-matching its stderr.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NixCliEvaluator;
+
+impl Evaluator for NixCliEvaluator {
+    fn instantiate(
+        &self,
+        root: &NixFile,
+        cas: &ContentAddressable,
+    ) -> Result<Info<OutputPaths<DrvFile>>, Error> {
+        instrumented_builder::instrumented_instantiation(root, cas)
+    }
+}
+
+/// Like `NixCliEvaluator`, but decodes `nix-instantiate`'s stderr via its
+/// machine-readable `--log-format internal-json` protocol instead of
+/// regex-matching `-vv` text. This only makes the *transport* robust (no
+/// more untangling interleaved output; it still passes `-vv`); it still
+/// regex-matches the same English log wording inside that envelope, so it's
+/// not a fix for Nix changing that wording (see
+/// `instrumented_builder::instrumented_instantiation_internal_json`). Prefer
+/// this evaluator once the minimum supported Nix version reliably supports
+/// `internal-json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NixCliInternalJsonEvaluator;
+
+impl Evaluator for NixCliInternalJsonEvaluator {
+    fn instantiate(
+        &self,
+        root: &NixFile,
+        cas: &ContentAddressable,
+    ) -> Result<Info<OutputPaths<DrvFile>>, Error> {
+        instrumented_builder::instrumented_instantiation_internal_json(root, cas)
+    }
+}
@@ -8,6 +8,8 @@
 //! `stderr`, like which source files are used by the evaluator.
 
 use cas::ContentAddressable;
+use nix::drv;
+use nix::evaluator::Evaluator;
 use regex::Regex;
 use std::any::Any;
 use std::ffi::{OsStr, OsString};
@@ -16,7 +18,12 @@ use std::process::{Command, Stdio};
 use vec1::Vec1;
 use {DrvFile, NixFile, StorePath};
 
-fn instrumented_instantiation(
+/// The `nix-instantiate`-backed implementation behind `evaluator::NixCliEvaluator`.
+///
+/// Decodes `nix-instantiate`'s stderr by regex-matching the human-readable
+/// `-vv` text (see `parse_evaluation_line`). For the `--log-format
+/// internal-json` variant, see `instrumented_instantiation_internal_json`.
+pub(crate) fn instrumented_instantiation(
     root_nix_file: &NixFile,
     cas: &ContentAddressable,
 ) -> Result<Info<OutputPaths<DrvFile>>, Error> {
@@ -27,7 +34,53 @@ fn instrumented_instantiation(
     //
     // to determine which files we should setup watches on.
     // Increasing verbosity by two levels via `-vv` satisfies that.
+    instrumented_instantiation_with(
+        root_nix_file,
+        cas,
+        &[OsStr::new("-vv")],
+        parse_evaluation_line,
+    )
+}
+
+/// Like `instrumented_instantiation`, but decodes `nix-instantiate`'s stderr
+/// using its machine-readable `--log-format internal-json` protocol (see
+/// `parse_internal_json_line`) instead of regex-matching raw `-vv` text.
+///
+/// This only protects against Nix changing *how* a log message reaches us
+/// (line framing): the JSON envelope is a stable contract, so we no longer
+/// worry about output interleaving. It still passes `-vv`, because the
+/// messages below are only logged at that verbosity. It does **not** protect against Nix changing the *wording*
+/// of "evaluating file '...'"/"copied source '...'" themselves — those are
+/// plain debug-log text carried in the envelope's `msg` field (Nix has no
+/// structured activity type for evaluator file reads), so
+/// `parse_internal_json_line` still matches them with `parse_evaluation_line`'s
+/// regexes and is exactly as sensitive to a wording change as the `-vv` path.
+pub(crate) fn instrumented_instantiation_internal_json(
+    root_nix_file: &NixFile,
+    cas: &ContentAddressable,
+) -> Result<Info<OutputPaths<DrvFile>>, Error> {
+    instrumented_instantiation_with(
+        root_nix_file,
+        cas,
+        &[
+            OsStr::new("-vv"),
+            OsStr::new("--log-format"),
+            OsStr::new("internal-json"),
+        ],
+        parse_internal_json_line,
+    )
+}
 
+/// Shared implementation of `instrumented_instantiation` and
+/// `instrumented_instantiation_internal_json`: spawns `nix-instantiate`
+/// with `extra_args` and folds its stderr, decoded line-by-line with
+/// `decode_line`, into the paths/attributes/log lines we care about.
+fn instrumented_instantiation_with(
+    root_nix_file: &NixFile,
+    cas: &ContentAddressable,
+    extra_args: &[&OsStr],
+    decode_line: impl Fn(&OsStr) -> LogDatum,
+) -> Result<Info<OutputPaths<DrvFile>>, Error> {
     let mut cmd = Command::new("nix-instantiate");
 
     let logged_evaluation_nix = cas.file_from_string(include_str!("./logged-evaluation.nix"))?;
@@ -35,55 +88,47 @@ fn instrumented_instantiation(
     // TODO: see ::nix::CallOpts::paths for the problem with this
     let gc_root_dir = tempfile::TempDir::new()?;
 
-    cmd.args(&[
-        // verbose mode prints the files we track
-        OsStr::new("-vv"),
-        // we add a temporary indirect GC root
-        OsStr::new("--add-root"),
-        gc_root_dir.path().join("result").as_os_str(),
-        OsStr::new("--indirect"),
-        OsStr::new("--argstr"),
-        // runtime nix paths to needed dependencies that come with lorri
-        OsStr::new("runTimeClosure"),
-        OsStr::new(crate::RUN_TIME_CLOSURE),
-        // the source file
-        OsStr::new("--argstr"),
-        OsStr::new("src"),
-        root_nix_file.as_os_str(),
-        // instrumented by `./logged-evaluation.nix`
-        OsStr::new("--"),
-        &logged_evaluation_nix.as_os_str(),
-    ])
-    .stdin(Stdio::null())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped());
+    cmd.args(extra_args)
+        .args(&[
+            // we add a temporary indirect GC root
+            OsStr::new("--add-root"),
+            gc_root_dir.path().join("result").as_os_str(),
+            OsStr::new("--indirect"),
+            OsStr::new("--argstr"),
+            // runtime nix paths to needed dependencies that come with lorri
+            OsStr::new("runTimeClosure"),
+            OsStr::new(crate::RUN_TIME_CLOSURE),
+            // the source file
+            OsStr::new("--argstr"),
+            OsStr::new("src"),
+            root_nix_file.as_os_str(),
+            // instrumented by `./logged-evaluation.nix`
+            OsStr::new("--"),
+            &logged_evaluation_nix.as_os_str(),
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
 
     debug!("$ {:?}", cmd);
 
     let output = cmd.spawn()?.wait_with_output()?;
 
-    let stderr_results =
-        ::nix::parse_nix_output(&output.stderr, |line| parse_evaluation_line(line));
+    let stderr_results = ::nix::parse_nix_output(&output.stderr, |line| decode_line(line));
 
-    let produced_drvs = Vec1::from_vec(::nix::parse_nix_output(&output.stdout, StorePath::from))
-        // programming error
-        .unwrap_or_else(|_| {
-            panic!(
-                "`lorri read` didn’t get a store path in its output:\n{:#?}",
-                stderr_results.clone()
-            )
-        });
+    let produced_drvs = ::nix::parse_nix_output(&output.stdout, StorePath::from);
 
     // iterate over all lines, parsing out the ones we are interested in
-    let (paths, output_paths, log_lines): (
+    let (paths, output_paths, log_lines, protocol_errors): (
         Vec<PathBuf>,
         // `None` if the field was not seen before, `Some` if it was
         OutputPaths<Option<DrvFile>>,
-        Vec<OsString>
+        Vec<OsString>,
+        Vec<ProtocolError>,
     ) =
     stderr_results.clone().into_iter().fold(
-        (vec![], OutputPaths { shell: None, shell_gc_root: None }, vec![]),
-        |(mut paths, mut output_paths, mut log_lines), result| {
+        (vec![], OutputPaths { shell: None, shell_gc_root: None, shell_structured: None }, vec![], vec![]),
+        |(mut paths, mut output_paths, mut log_lines, mut protocol_errors), result| {
                 match result {
                     LogDatum::Source(src) => {
                         paths.push(src);
@@ -92,29 +137,61 @@ fn instrumented_instantiation(
                         // check whether we have seen this field before
                         match output_paths.shell {
                             None => { output_paths.shell = Some(DrvFile(drv)); }
-                            // programming error
-                            Some(DrvFile(old)) => panic!(
-                                "`lorri read` got attribute `{}` a second time, first path was {:?} and second {:?}",
-                                "shell", old, drv)
+                            Some(DrvFile(ref old)) => protocol_errors.push(
+                                ProtocolError::DuplicateAttribute {
+                                    attribute: "shell".to_string(),
+                                    first_path: old.clone(),
+                                    second_path: drv,
+                                    stderr_results: stderr_results.clone(),
+                                },
+                            ),
                         }
                     },
                     LogDatum::ShellGcRootDrv(drv) => {
                         // check whether we have seen this field before
                         match output_paths.shell_gc_root {
                             None => { output_paths.shell_gc_root = Some(DrvFile(drv)); }
-                            // programming error
-                            Some(DrvFile(old)) => panic!(
-                                "`lorri read` got attribute `{}` a second time, first path was {:?} and second {:?}",
-                                "shell_gc_root", old, drv)
+                            Some(DrvFile(ref old)) => protocol_errors.push(
+                                ProtocolError::DuplicateAttribute {
+                                    attribute: "shell_gc_root".to_string(),
+                                    first_path: old.clone(),
+                                    second_path: drv,
+                                    stderr_results: stderr_results.clone(),
+                                },
+                            ),
+                        }
+                    },
+                    LogDatum::ShellStructuredDrv(drv) => {
+                        // check whether we have seen this field before
+                        match output_paths.shell_structured {
+                            None => { output_paths.shell_structured = Some(DrvFile(drv)); }
+                            Some(DrvFile(ref old)) => protocol_errors.push(
+                                ProtocolError::DuplicateAttribute {
+                                    attribute: "shell_structured".to_string(),
+                                    first_path: old.clone(),
+                                    second_path: drv,
+                                    stderr_results: stderr_results.clone(),
+                                },
+                            ),
                         }
                     },
+                    LogDatum::UnknownAttribute(attribute) => {
+                        protocol_errors.push(ProtocolError::UnknownAttribute {
+                            attribute,
+                            stderr_results: stderr_results.clone(),
+                        });
+                    }
                     LogDatum::Text(line) => log_lines.push(line),
                 };
 
-                (paths, output_paths, log_lines)
+                (paths, output_paths, log_lines, protocol_errors)
             },
         );
 
+    if let Some(err) = protocol_errors.into_iter().next() {
+        return Err(Error::Protocol(err));
+    }
+
     if !output.status.success() {
         return Ok(Info::Failure(Failure {
             exec_result: output.status,
@@ -124,28 +201,45 @@ fn instrumented_instantiation(
 
     // check whether we got all required `OutputPaths`
     let output_paths = match output_paths {
-        // programming error
-        OutputPaths { shell: None, .. } => panic!(
-            "`lorri read` never got required attribute `shell:\n{:#?}`",
-            stderr_results
-        ),
-        // programming error
+        OutputPaths { shell: None, .. } => {
+            return Err(Error::Protocol(ProtocolError::MissingAttribute {
+                attribute: "shell".to_string(),
+                stderr_results,
+            }))
+        }
         OutputPaths {
             shell_gc_root: None,
             ..
-        } => panic!(
-            "`lorri read` never got required attribute `shell_gc_root`\n{:#?}",
-            stderr_results
-        ),
+        } => {
+            return Err(Error::Protocol(ProtocolError::MissingAttribute {
+                attribute: "shell_gc_root".to_string(),
+                stderr_results,
+            }))
+        }
+        OutputPaths {
+            shell_structured: None,
+            ..
+        } => {
+            return Err(Error::Protocol(ProtocolError::MissingAttribute {
+                attribute: "shell_structured".to_string(),
+                stderr_results,
+            }))
+        }
         OutputPaths {
             shell: Some(shell),
             shell_gc_root: Some(shell_gc_root),
+            shell_structured: Some(shell_structured),
         } => OutputPaths {
             shell,
             shell_gc_root,
+            shell_structured,
         },
     };
 
+    let produced_drvs = Vec1::from_vec(produced_drvs).map_err(|_| {
+        Error::Protocol(ProtocolError::NoStorePathProduced { stderr_results })
+    })?;
+
     Ok(Info::Success(Success {
         drvs: (produced_drvs, ::nix::GcRootTempDir(gc_root_dir)),
         output_paths,
@@ -157,33 +251,64 @@ fn instrumented_instantiation(
 ///
 /// Instruments the nix file to gain extra information,
 /// which is valuable even if the build fails.
-pub fn run(root_nix_file: &NixFile, cas: &ContentAddressable) -> Result<Info<StorePath>, Error> {
-    let inst_info = instrumented_instantiation(root_nix_file, cas)?;
-    match inst_info {
-        Info::Success(s) => {
-            let drvs = s.output_paths.clone();
-            // TODO: we are only using shell_gc_root here, I don’t think
-            // we are using the shell anywhere anymore. Then we could remove
-            // it from OutputPaths and simplify logged-evaluation.nix!
-            let realized = ::nix::CallOpts::file(drvs.shell_gc_root.as_path()).path()?;
-            match s {
-                Success { paths, .. } => Ok(Info::Success(Success {
-                    // TODO: duplication, remove drvs in favour of output_paths
-                    drvs: (vec1::vec1![realized.0.clone()], realized.1),
-                    output_paths: realized.0,
-                    paths,
-                })),
-            }
-        }
-        Info::Failure(f) => Ok(Info::Failure(f)),
+///
+/// `evaluator` decides how the expression is instantiated; pass
+/// `&evaluator::NixCliEvaluator` for the existing `nix-instantiate` behavior.
+///
+/// `cached_shell_gc_root` is a `.drv` produced by a previous `run`, if the
+/// caller has one. When its full `inputDrvs`/`inputSrcs` closure is still
+/// present in the store (see `drv_closure_present`), we skip the evaluator
+/// entirely and go straight to re-realizing it, avoiding an unnecessary
+/// re-evaluation. Otherwise (no cache, or `nix-store --gc` ran since it was
+/// last built) we fall back to asking `evaluator` for a fresh one.
+pub fn run(
+    evaluator: &impl Evaluator,
+    root_nix_file: &NixFile,
+    cas: &ContentAddressable,
+    cached_shell_gc_root: Option<&DrvFile>,
+) -> Result<Info<StorePath>, Error> {
+    let (shell_gc_root, mut paths) = match cached_shell_gc_root {
+        Some(drv) if drv_closure_present(drv) => (drv.clone(), vec![]),
+        _ => match evaluator.instantiate(root_nix_file, cas)? {
+            Info::Success(Success {
+                output_paths, paths, ..
+            }) => (output_paths.shell_gc_root, paths),
+            Info::Failure(f) => return Ok(Info::Failure(f)),
+            Info::Stale(s) => return Ok(Info::Stale(s)),
+        },
+    };
+
+    // TODO: we are only using shell_gc_root here, I don’t think
+    // we are using the shell anywhere anymore. Then we could remove
+    // it from OutputPaths and simplify logged-evaluation.nix!
+    let realized = ::nix::CallOpts::file(shell_gc_root.as_path()).path()?;
+
+    // Augment the log-scraped source paths (empty if we skipped evaluation
+    // above) with the precise set recovered by walking the realized
+    // derivation’s `inputDrvs`/`inputSrcs` closure. If the `.drv` can’t be
+    // read for some reason, fall back to what we already have rather than
+    // failing the whole build.
+    if let Ok(closure_paths) = drv::transitive_closure(&shell_gc_root) {
+        paths.extend(closure_paths);
     }
+
+    Ok(Info::Success(Success {
+        // TODO: duplication, remove drvs in favour of output_paths
+        drvs: (vec1::vec1![realized.0.clone()], realized.1),
+        output_paths: realized.0,
+        paths,
+    }))
 }
 
 #[derive(Debug, PartialEq, Clone)]
-enum LogDatum {
+pub(crate) enum LogDatum {
     Source(PathBuf),
     ShellDrv(PathBuf),
     ShellGcRootDrv(PathBuf),
+    ShellStructuredDrv(PathBuf),
+    /// `trace: lorri attribute: '<attribute>' -> ...` for an attribute we
+    /// don’t know how to handle (see `ProtocolError::UnknownAttribute`).
+    UnknownAttribute(String),
     Text(OsString),
 }
 
@@ -221,10 +346,8 @@ fn parse_evaluation_line(line: &OsStr) -> LogDatum {
                 match attr {
                     "shell" => LogDatum::ShellDrv(PathBuf::from(drv)),
                     "shell_gc_root" => LogDatum::ShellGcRootDrv(PathBuf::from(drv)),
-                    _ => panic!(
-                        "`lorri read` trace was `{} -> {}`, unknown attribute `{}`! (add to `builder.rs`)",
-                        attr, drv, attr
-                    ),
+                    "shell_structured" => LogDatum::ShellStructuredDrv(PathBuf::from(drv)),
+                    _ => LogDatum::UnknownAttribute(attr.to_string()),
                 }
             } else {
                 LogDatum::Text(line.to_owned())
@@ -233,6 +356,44 @@ fn parse_evaluation_line(line: &OsStr) -> LogDatum {
     }
 }
 
+/// A single `nix-instantiate --log-format internal-json` stderr record,
+/// i.e. the JSON object following the `@nix ` prefix on a `msg` line.
+///
+/// We only care about `"action": "msg"` records, which carry the exact
+/// same English text `parse_evaluation_line` already knows how to match
+/// (Nix emits its human-readable log messages through this field
+/// regardless of log format, and has no structured activity type for
+/// evaluator file reads); other actions (`start`/`stop`/`result` activity
+/// tracking) are passed through untouched.
+#[derive(serde::Deserialize)]
+struct InternalJsonRecord {
+    action: String,
+    #[serde(default)]
+    msg: Option<String>,
+}
+
+/// Like `parse_evaluation_line`, but decodes a `--log-format internal-json`
+/// stderr line instead of a `-vv` human-readable one. Each such line is
+/// `@nix {...}`; everything else (plain build output interleaved on
+/// stderr) is passed through as `LogDatum::Text`.
+fn parse_internal_json_line(line: &OsStr) -> LogDatum {
+    match line.to_str() {
+        // If we can’t decode the output line to an UTF-8 string,
+        // we cannot match against the `@nix ` prefix, so just pass it through.
+        None => LogDatum::Text(line.to_owned()),
+        Some(linestr) => match linestr.strip_prefix("@nix ") {
+            None => LogDatum::Text(line.to_owned()),
+            Some(json) => match serde_json::from_str::<InternalJsonRecord>(json) {
+                Ok(InternalJsonRecord {
+                    action,
+                    msg: Some(msg),
+                }) if action == "msg" => parse_evaluation_line(&OsString::from(msg)),
+                _ => LogDatum::Text(line.to_owned()),
+            },
+        },
+    }
+}
+
 /// The results of an individual instantiation/build.
 /// Even if the exit code is not 0, there is still
 /// valuable information in the output, like new paths
@@ -243,6 +404,53 @@ pub enum Info<T> {
     Success(Success<T>),
     /// Nix returned a failing status code.
     Failure(Failure),
+    /// The evaluated derivation is syntactically intact, but part of its
+    /// input closure has been garbage collected, so it cannot just be
+    /// re-realized; it has to be evaluated again (see `drv_closure_present`).
+    Stale(Stale),
+}
+
+/// A cached derivation whose input closure is no longer fully present
+/// in the Nix store, e.g. because `nix-store --gc` ran since it was
+/// last built. See `drv_closure_present`.
+#[derive(Debug)]
+pub struct Stale {
+    /// The first missing store path we found while walking the closure.
+    pub missing: PathBuf,
+}
+
+/// Recursively check that a `.drv` file and every path in its
+/// `inputDrvs`/`inputSrcs` closure is still present in the Nix store,
+/// short-circuiting to `false` on the first missing path.
+///
+/// This lets callers tell a derivation that still needs a full
+/// re-evaluation apart from one that is intact and only needs to be
+/// re-realized.
+pub fn drv_closure_present(drv: &DrvFile) -> bool {
+    first_missing_closure_input(drv.as_path()).is_none()
+}
+
+/// Like `drv_closure_present`, but returns the first missing path
+/// instead of a plain `bool`.
+fn first_missing_closure_input(path: &std::path::Path) -> Option<PathBuf> {
+    if !path.exists() {
+        return Some(path.to_path_buf());
+    }
+    match drv::read_derivation(path) {
+        Ok(parsed) => parsed
+            .input_srcs
+            .iter()
+            .find(|src| !src.exists())
+            .cloned()
+            .or_else(|| {
+                parsed
+                    .input_drvs
+                    .iter()
+                    .find_map(|(drv, _outputs)| first_missing_closure_input(drv))
+            }),
+        // we couldn’t even read the `.drv`, treat that as missing too
+        Err(_) => Some(path.to_path_buf()),
+    }
 }
 
 /// A successful Nix run.
@@ -278,6 +486,9 @@ pub struct OutputPaths<T> {
     pub shell: T,
     /// Shell derivation modified to work as a gc root
     pub shell_gc_root: T,
+    /// Shell derivation's `__structuredAttrs` output (`.attrs.json`/`.attrs.sh`),
+    /// or an alias of `shell_gc_root` when the shell doesn't use `__structuredAttrs`.
+    pub shell_structured: T,
 }
 
 /// Return the name of each `OutputPaths` attribute.
@@ -285,6 +496,7 @@ pub fn output_path_attr_names() -> OutputPaths<String> {
     OutputPaths {
         shell: String::from("shell"),
         shell_gc_root: String::from("shell_gc_root"),
+        shell_structured: String::from("shell_structured"),
     }
 }
 
@@ -297,6 +509,7 @@ impl<T> OutputPaths<T> {
         OutputPaths {
             shell: f(self.shell),
             shell_gc_root: f(self.shell_gc_root),
+            shell_structured: f(self.shell_structured),
         }
     }
 
@@ -308,6 +521,7 @@ impl<T> OutputPaths<T> {
         Ok(OutputPaths {
             shell: f(self.shell)?,
             shell_gc_root: f(self.shell_gc_root)?,
+            shell_structured: f(self.shell_structured)?,
         })
     }
 
@@ -316,6 +530,7 @@ impl<T> OutputPaths<T> {
         OutputPaths {
             shell: (self.shell, us.shell),
             shell_gc_root: (self.shell_gc_root, us.shell_gc_root),
+            shell_structured: (self.shell_structured, us.shell_structured),
         }
     }
 }
@@ -331,7 +546,51 @@ pub enum Error {
 
     /// Failed to spawn a log processing thread
     ThreadFailure(std::boxed::Box<(dyn std::any::Any + std::marker::Send + 'static)>),
+
+    /// The evaluator’s output didn’t follow the expected `lorri read`/`lorri
+    /// attribute` protocol. This is a bug in the instrumentation or in the
+    /// evaluator, not a true programming error in lorri, so it’s returned
+    /// rather than panicking a long-running daemon.
+    Protocol(ProtocolError),
+}
+
+/// A violation of the `lorri read`/`lorri attribute` protocol that
+/// `instrumented_instantiation` expects the evaluator to follow.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// A required `OutputPaths` attribute was never reported.
+    MissingAttribute {
+        /// The attribute we never saw.
+        attribute: String,
+        /// Everything else we parsed out of stderr, for diagnostics.
+        stderr_results: Vec<LogDatum>,
+    },
+    /// An `OutputPaths` attribute was reported more than once.
+    DuplicateAttribute {
+        /// The attribute we saw twice.
+        attribute: String,
+        /// The store path reported the first time.
+        first_path: PathBuf,
+        /// The store path reported the second time.
+        second_path: PathBuf,
+        /// Everything else we parsed out of stderr, for diagnostics.
+        stderr_results: Vec<LogDatum>,
+    },
+    /// `parse_evaluation_line`/`parse_internal_json_line` saw a `lorri
+    /// attribute` trace for an attribute name it doesn’t know how to route.
+    UnknownAttribute {
+        /// The attribute name we don’t recognize.
+        attribute: String,
+        /// Everything else we parsed out of stderr, for diagnostics.
+        stderr_results: Vec<LogDatum>,
+    },
+    /// `nix-instantiate`’s stdout (the produced derivations’ store paths) was empty.
+    NoStorePathProduced {
+        /// Everything we parsed out of stderr, for diagnostics.
+        stderr_results: Vec<LogDatum>,
+    },
 }
+
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
         Error::Instantiate(e)
@@ -350,7 +609,10 @@ impl From<Box<dyn Any + Send + 'static>> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_evaluation_line, LogDatum};
+    use super::{
+        drv, drv_closure_present, parse_evaluation_line, parse_internal_json_line, DrvFile,
+        LogDatum,
+    };
     use std::ffi::OsString;
     use std::path::PathBuf;
 
@@ -383,6 +645,10 @@ mod tests {
             parse_evaluation_line(&OsString::from("trace: lorri attribute: 'shell_gc_root' -> '/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri-keep-env-hack-foo.drv'")),
             LogDatum::ShellGcRootDrv(PathBuf::from("/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri-keep-env-hack-foo.drv"))
         );
+        assert_eq!(
+            parse_evaluation_line(&OsString::from("trace: lorri attribute: 'shell_structured' -> '/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri-structured-attrs.drv'")),
+            LogDatum::ShellStructuredDrv(PathBuf::from("/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri-structured-attrs.drv"))
+        );
 
         assert_eq!(
             parse_evaluation_line(&OsString::from(
@@ -396,6 +662,152 @@ mod tests {
 
     #[test]
     fn transitive_source_file_detection() -> std::io::Result<()> {
+        let tempdir = tempfile::TempDir::new()?;
+        let dep_drv = tempdir.path().join("dep.drv");
+        let root_drv = tempdir.path().join("root.drv");
+        let dep_src = tempdir.path().join("dep_src");
+        let root_src = tempdir.path().join("root_src");
+
+        std::fs::write(
+            &dep_drv,
+            format!(
+                r#"Derive([("out","/nix/store/dep-out","","")],[],["{}"],"x","y",[],[])"#,
+                dep_src.display()
+            ),
+        )?;
+        std::fs::write(
+            &root_drv,
+            format!(
+                r#"Derive([("out","/nix/store/root-out","","")],[("{}",["out"])],["{}"],"x","y",[],[])"#,
+                dep_drv.display(),
+                root_src.display()
+            ),
+        )?;
+
+        let mut closure = drv::transitive_closure(&DrvFile(root_drv.clone()))
+            .expect("should walk the closure");
+        closure.sort();
+
+        let mut expected = vec![root_drv, dep_drv, root_src, dep_src];
+        expected.sort();
+
+        assert_eq!(closure, expected);
+
         Ok(())
     }
+
+    /// Write a minimal two-level `.drv` chain (`root.drv` -> `dep.drv`) into
+    /// `dir`, with each level referencing one plain source file. Returns the
+    /// `root.drv` path. Callers control which referenced files actually
+    /// exist on disk, to exercise `drv_closure_present`'s short-circuiting.
+    fn write_drv_chain(
+        dir: &std::path::Path,
+        dep_src_exists: bool,
+        root_src_exists: bool,
+    ) -> PathBuf {
+        let dep_drv = dir.join("dep.drv");
+        let root_drv = dir.join("root.drv");
+        let dep_src = dir.join("dep_src");
+        let root_src = dir.join("root_src");
+
+        if dep_src_exists {
+            std::fs::write(&dep_src, "").unwrap();
+        }
+        if root_src_exists {
+            std::fs::write(&root_src, "").unwrap();
+        }
+
+        std::fs::write(
+            &dep_drv,
+            format!(
+                r#"Derive([("out","/nix/store/dep-out","","")],[],["{}"],"x","y",[],[])"#,
+                dep_src.display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            &root_drv,
+            format!(
+                r#"Derive([("out","/nix/store/root-out","","")],[("{}",["out"])],["{}"],"x","y",[],[])"#,
+                dep_drv.display(),
+                root_src.display()
+            ),
+        )
+        .unwrap();
+
+        root_drv
+    }
+
+    #[test]
+    fn drv_closure_present_when_everything_exists() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let root_drv = write_drv_chain(tempdir.path(), true, true);
+        assert!(drv_closure_present(&DrvFile(root_drv)));
+    }
+
+    #[test]
+    fn drv_closure_present_false_when_a_direct_input_src_is_missing() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let root_drv = write_drv_chain(tempdir.path(), true, false);
+        assert!(!drv_closure_present(&DrvFile(root_drv)));
+    }
+
+    #[test]
+    fn drv_closure_present_false_when_a_nested_input_src_is_missing() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let root_drv = write_drv_chain(tempdir.path(), false, true);
+        assert!(!drv_closure_present(&DrvFile(root_drv)));
+    }
+
+    #[test]
+    fn drv_closure_present_false_when_the_drv_itself_is_gone() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let missing_drv = tempdir.path().join("never-written.drv");
+        assert!(!drv_closure_present(&DrvFile(missing_drv)));
+    }
+
+    #[test]
+    fn drv_closure_present_false_when_a_drv_is_unreadable() {
+        let tempdir = tempfile::TempDir::new().unwrap();
+        let corrupt_drv = tempdir.path().join("corrupt.drv");
+        std::fs::write(&corrupt_drv, "not an ATerm derivation").unwrap();
+        assert!(!drv_closure_present(&DrvFile(corrupt_drv)));
+    }
+
+    #[test]
+    fn test_internal_json_line_to_path_evaluation() {
+        assert_eq!(
+            parse_internal_json_line(&OsString::from(
+                r#"@nix {"action":"msg","level":4,"msg":"evaluating file '/nix/store/zqxha3ax0w771jf25qdblakka83660gr-source/lib/systems/for-meta.nix'"}"#
+            )),
+            LogDatum::Source(PathBuf::from(
+                "/nix/store/zqxha3ax0w771jf25qdblakka83660gr-source/lib/systems/for-meta.nix"
+            ))
+        );
+
+        assert_eq!(
+            parse_internal_json_line(&OsString::from(
+                r#"@nix {"action":"msg","level":4,"msg":"trace: lorri attribute: 'shell' -> '/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri.drv'"}"#
+            )),
+            LogDatum::ShellDrv(PathBuf::from(
+                "/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri.drv"
+            ))
+        );
+
+        // non-`msg` activity records (e.g. start/stop) are passed through
+        assert_eq!(
+            parse_internal_json_line(&OsString::from(
+                r#"@nix {"action":"start","id":1,"level":4,"type":10,"text":"copying path"}"#
+            )),
+            LogDatum::Text(OsString::from(
+                r#"@nix {"action":"start","id":1,"level":4,"type":10,"text":"copying path"}"#
+            ))
+        );
+
+        // plain build output interleaved on stderr is passed through too
+        assert_eq!(
+            parse_internal_json_line(&OsString::from("these are the voyages")),
+            LogDatum::Text(OsString::from("these are the voyages"))
+        );
+    }
 }
\ No newline at end of file
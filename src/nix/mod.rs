@@ -0,0 +1,6 @@
+//! Talking to Nix: running `nix-instantiate`/`nix-build` and parsing
+//! their output.
+
+pub mod drv;
+pub mod evaluator;
+pub mod instrumented_builder;